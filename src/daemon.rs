@@ -0,0 +1,242 @@
+use crate::net::AddressFamily;
+use crate::ntp::{self, KissCode, NTPResults, NTPSample, NtpError};
+use anyhow::{bail, Result};
+use chrono::Duration;
+use std::collections::VecDeque;
+use std::thread;
+use std::time::Duration as StdDuration;
+
+/// Number of samples kept in the rolling clock filter window, matching `NTPResults`'s
+/// fixed-size burst in `ntp::ntp_query`.
+const WINDOW: usize = 8;
+
+const MIN_POLL_SECS: f64 = 5.0;
+const MAX_POLL_SECS: f64 = 60.0;
+
+/// Offsets beyond this are stepped with `settimeofday` instead of slewed with `adjtime`.
+const STEP_THRESHOLD_SECS: i64 = 180;
+
+const TRUST_MAX: i32 = 8;
+const TRUST_MIN: i32 = 0;
+
+/// The outcome of checking a freshly-polled sample against the peer's current filter.
+#[derive(Debug, PartialEq, Eq)]
+enum Agreement {
+    /// The sample agreed closely enough with the filter to be folded in and applied.
+    Agreed,
+    /// The sample disagreed; it is discarded rather than polluting the window.
+    Disagreed,
+    /// The sample disagreed and trust has now run out; the peer's window is reset.
+    Dropped,
+}
+
+/// Per-peer clock filter state, persisted across polls.
+struct Peer {
+    host: String,
+    port: u16,
+    samples: VecDeque<NTPSample>,
+    poll_interval: f64,
+    trust: i32,
+}
+
+impl Peer {
+    fn new(host: &str, port: u16) -> Self {
+        Peer {
+            host: host.to_string(),
+            port,
+            samples: VecDeque::with_capacity(WINDOW),
+            poll_interval: MIN_POLL_SECS,
+            trust: TRUST_MAX / 2,
+        }
+    }
+
+    fn push_sample(&mut self, sample: NTPSample) {
+        if self.samples.len() == WINDOW {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(sample);
+    }
+
+    /// A snapshot of the current window as an `NTPResults`, reusing its existing
+    /// lowest-delay selection and jitter calculation.
+    fn filtered(&self) -> NTPResults {
+        NTPResults::from_samples(self.samples.iter().cloned().collect())
+    }
+
+    /// Checks `sample`'s offset against the current filtered window and updates trust and
+    /// the poll interval accordingly. Returns whether the sample agreed closely enough to be
+    /// folded into the window; a disagreeing sample must not be pushed or applied to the
+    /// clock, or a single bad reading would step the clock right before the peer is dropped.
+    /// With fewer than 2 samples there's no filter yet to check against, so it always agrees.
+    fn check_agreement(&mut self, sample: &NTPSample) -> Agreement {
+        if self.samples.len() < 2 {
+            return Agreement::Agreed;
+        }
+
+        let filtered = self.filtered();
+        let jitter = Duration::nanoseconds((filtered.jitter() * 1e9) as i64)
+            .max(Duration::milliseconds(1));
+        let disagreement = sample.offset - filtered.min_offset();
+
+        if disagreement.abs() <= jitter {
+            self.trust = (self.trust + 1).min(TRUST_MAX);
+            self.poll_interval = (self.poll_interval * 1.5).min(MAX_POLL_SECS);
+            Agreement::Agreed
+        } else {
+            self.trust -= 1;
+            self.poll_interval = MIN_POLL_SECS;
+            if self.trust <= TRUST_MIN {
+                self.samples.clear();
+                self.trust = TRUST_MAX / 2;
+                Agreement::Dropped
+            } else {
+                Agreement::Disagreed
+            }
+        }
+    }
+}
+
+/// Runs rrdate as a disciplining daemon against a single NTP peer: polls it on an adaptive
+/// interval, filters samples the way `NTPResults` already does, and steps or slews the local
+/// clock depending on how large the filtered offset is. A single peer whose offsets grossly
+/// disagree with the filtered estimate has its trust level lowered until it is dropped.
+pub fn run(host: &str, port: u16, verbose: u8, family: AddressFamily, print: bool) -> Result<()> {
+    let mut peer = Peer::new(host, port);
+
+    loop {
+        match ntp::ntp_roundtrip(&peer.host, peer.port, family) {
+            Ok(timestamps) => {
+                let sample = timestamps.sample();
+
+                match peer.check_agreement(&sample) {
+                    Agreement::Agreed => {
+                        let delay = sample.delay;
+                        peer.push_sample(sample);
+                        let selected = peer.filtered().min_offset();
+
+                        if verbose > 0 {
+                            let best = peer.filtered();
+                            let best = best.best().expect("just pushed a sample");
+                            println!(
+                                "{}: offset {:.6}s delay {:.6}s trust {} poll {:.0}s stratum {} refid {}",
+                                peer.host,
+                                selected.num_microseconds().unwrap_or(0) as f64 / 1e6,
+                                delay.num_microseconds().unwrap_or(0) as f64 / 1e6,
+                                peer.trust,
+                                peer.poll_interval,
+                                best.stratum,
+                                best.reference_id,
+                            );
+                        }
+
+                        apply_offset(selected, print)?;
+                    }
+                    Agreement::Disagreed => {
+                        if verbose > 0 {
+                            println!(
+                                "{}: offset {:.6}s disagreed with the filtered estimate, discarding sample, trust {} poll {:.0}s",
+                                peer.host,
+                                sample.offset.num_microseconds().unwrap_or(0) as f64 / 1e6,
+                                peer.trust,
+                                peer.poll_interval,
+                            );
+                        }
+                    }
+                    Agreement::Dropped => {
+                        eprintln!(
+                            "Dropping peer {}: offset disagrees with the filtered estimate too often",
+                            peer.host
+                        );
+                    }
+                }
+            }
+            Err(err) => match err.downcast_ref::<NtpError>() {
+                Some(NtpError::KissOfDeath { code, .. }) => match code {
+                    KissCode::RateLimit => {
+                        eprintln!(
+                            "{}: server asked us to slow down (Kiss-o'-Death RATE), backing off",
+                            peer.host
+                        );
+                        peer.poll_interval = (peer.poll_interval * 2.0).min(MAX_POLL_SECS);
+                    }
+                    KissCode::Denied => {
+                        bail!(
+                            "{}: server permanently refused service (Kiss-o'-Death); stopping",
+                            peer.host
+                        );
+                    }
+                    KissCode::Other => {
+                        eprintln!("NTP query to {} failed: {}", peer.host, err);
+                        peer.poll_interval = MIN_POLL_SECS;
+                    }
+                },
+                None => {
+                    eprintln!("NTP query to {} failed: {}", peer.host, err);
+                    peer.poll_interval = MIN_POLL_SECS;
+                }
+            },
+        }
+
+        thread::sleep(StdDuration::from_secs_f64(peer.poll_interval));
+    }
+}
+
+/// Steps the clock via `settimeofday` for large offsets, otherwise slews it via `adjtime`.
+/// With `print` set, the correction is computed but never applied, for dry runs.
+fn apply_offset(offset: Duration, print: bool) -> Result<()> {
+    if print {
+        return Ok(());
+    }
+    let slew = offset.abs() <= Duration::seconds(STEP_THRESHOLD_SECS);
+    crate::set_clock(offset, slew)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(offset_ms: i64, delay_ms: i64) -> NTPSample {
+        NTPSample {
+            offset: Duration::milliseconds(offset_ms),
+            delay: Duration::milliseconds(delay_ms),
+            stratum: 1,
+            reference_id: "TEST".to_string(),
+            root_delay: Duration::zero(),
+            root_dispersion: Duration::zero(),
+        }
+    }
+
+    #[test]
+    fn agreeing_sample_raises_trust_and_poll_interval() {
+        let mut peer = Peer::new("test", 123);
+        peer.push_sample(sample(10, 20));
+        peer.push_sample(sample(11, 20));
+        let trust_before = peer.trust;
+        let poll_before = peer.poll_interval;
+
+        let agreement = peer.check_agreement(&sample(10, 20));
+
+        assert_eq!(agreement, Agreement::Agreed);
+        assert_eq!(peer.trust, (trust_before + 1).min(TRUST_MAX));
+        assert!(peer.poll_interval > poll_before);
+    }
+
+    #[test]
+    fn gross_disagreement_drops_peer_without_polluting_the_window() {
+        let mut peer = Peer::new("test", 123);
+        peer.push_sample(sample(10, 20));
+        peer.push_sample(sample(11, 20));
+
+        let outlier = sample(5_000, 20);
+        let starting_trust = peer.trust;
+        for _ in 0..starting_trust - 1 {
+            assert_eq!(peer.check_agreement(&outlier), Agreement::Disagreed);
+        }
+        assert_eq!(peer.check_agreement(&outlier), Agreement::Dropped);
+
+        // The peer is reset, and the outlier that triggered the drop was never folded
+        // into the window or applied to the clock.
+        assert!(peer.samples.is_empty());
+        assert_eq!(peer.trust, TRUST_MAX / 2);
+    }
+}
@@ -2,15 +2,23 @@ use anyhow::{bail, Result};
 use chrono::Duration;
 use clap::{ArgAction, Parser};
 
+mod daemon;
+mod net;
 mod ntp;
 mod rfc868;
+mod skew;
+
+use net::AddressFamily;
 
 /// A simple SNTP (RFC 5905) and RFC 868 client written in Rust.
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
 struct Cli {
-    /// Time server (e.g. time.nist.gov)
-    host: String,
+    /// Time server(s) (e.g. time.nist.gov). If several are given, each is queried
+    /// independently and the results are combined via a truechimer/falseticker selection
+    /// algorithm. --serve, --daemon, --discipline and --rfc868 only support a single host.
+    #[arg(required = true)]
+    hosts: Vec<String>,
 
     /// Verbose output
     #[arg(short, long, action = ArgAction::Count)]
@@ -40,6 +48,33 @@ struct Cli {
     /// Use RFC 868 time protocol instead of SNTP (RFC 5905).
     #[arg(long)]
     rfc868: bool,
+
+    /// Serve time instead of querying it. The host is then used as the bind address.
+    #[arg(long, conflicts_with = "daemon")]
+    serve: bool,
+
+    /// Keep running, continuously disciplining the local clock against the server instead
+    /// of exiting after a single correction.
+    #[arg(long, conflicts_with_all = ["rfc868", "serve"])]
+    daemon: bool,
+
+    /// Keep running, estimating the local oscillator's frequency drift (in addition to the
+    /// phase offset) and correcting both via adjtimex(2).
+    #[arg(long, alias = "skew", conflicts_with_all = ["rfc868", "daemon", "serve"])]
+    discipline: bool,
+
+    /// Number of worker threads to serve with, each sharing the bind address via SO_REUSEPORT.
+    /// Only relevant together with --serve.
+    #[arg(long, default_value_t = 1)]
+    threads: usize,
+
+    /// Resolve and connect using IPv4 addresses only.
+    #[arg(short = '4', conflicts_with = "force_ipv6")]
+    force_ipv4: bool,
+
+    /// Resolve and connect using IPv6 addresses only.
+    #[arg(short = '6', conflicts_with = "force_ipv4")]
+    force_ipv6: bool,
 }
 
 #[test]
@@ -92,6 +127,12 @@ fn main() -> Result<()> {
     let cli = Cli::parse();
     const TIME_PORT: u16 = 37;
 
+    let family = match (cli.force_ipv4, cli.force_ipv6) {
+        (true, _) => AddressFamily::V4,
+        (_, true) => AddressFamily::V6,
+        (false, false) => AddressFamily::Any,
+    };
+
     if cli.verbose > 0 {
         let precision = *ntp::RHO;
         println!(
@@ -101,22 +142,75 @@ fn main() -> Result<()> {
         );
     }
 
+    if cli.serve {
+        let host = single_host(&cli.hosts)?;
+        let port = cli
+            .port
+            .unwrap_or(if cli.rfc868 { TIME_PORT } else { ntp::NTP_PORT });
+        return match cli.rfc868 {
+            true => rfc868::serve(host, port, family),
+            false => ntp::serve(host, port, cli.threads, family),
+        };
+    }
+
+    if cli.daemon {
+        let host = single_host(&cli.hosts)?;
+        let port = cli.port.unwrap_or(ntp::NTP_PORT);
+        return daemon::run(host, port, cli.verbose, family, cli.print);
+    }
+
+    if cli.discipline {
+        let host = single_host(&cli.hosts)?;
+        let port = cli.port.unwrap_or(ntp::NTP_PORT);
+        return skew::run(host, port, cli.verbose, family, cli.print);
+    }
+
     let delta = match cli.rfc868 {
         true => {
+            let host = single_host(&cli.hosts)?;
             let port = cli.port.unwrap_or(TIME_PORT);
             match cli.udp {
-                true => rfc868::get_time_udp(&cli.host, port),
-                false => rfc868::get_time_tcp(&cli.host, port),
+                true => rfc868::get_time_udp(host, port, family),
+                false => rfc868::get_time_tcp(host, port, family),
             }?
         }
         false => {
             let port = cli.port.unwrap_or(ntp::NTP_PORT);
-            let results = ntp::ntp_query(&cli.host, port)?;
+            let mut peers = Vec::with_capacity(cli.hosts.len());
+            for host in &cli.hosts {
+                match ntp::ntp_query(host, port, family) {
+                    Ok(results) => {
+                        if cli.verbose > 0 {
+                            if let Some(best) = results.best() {
+                                println!(
+                                    "{}: offset {:.6}s jitter {:.1}μs delay {}ms stratum {} refid {} \
+                                     root delay {}ms root dispersion {}ms",
+                                    host,
+                                    results.min_offset().num_microseconds().unwrap_or(0) as f64
+                                        / 1e6,
+                                    results.jitter() * 1e6,
+                                    results.min_delay().num_milliseconds(),
+                                    best.stratum,
+                                    best.reference_id,
+                                    best.root_delay.num_milliseconds(),
+                                    best.root_dispersion.num_milliseconds(),
+                                );
+                            }
+                        }
+                        peers.push((host.clone(), results));
+                    }
+                    Err(err) => eprintln!("Skipping {}: {}", host, err),
+                }
+            }
+
+            let selection = ntp::select_peers(peers)?;
             if cli.verbose > 0 {
-                println!("Jitter: {:.1}μs", results.jitter() * 1e6);
-                println!("Delay: {}ms", results.min_delay().num_milliseconds());
+                println!("Selected: {}", selection.accepted.join(", "));
+                if !selection.rejected.is_empty() {
+                    println!("Rejected: {}", selection.rejected.join(", "));
+                }
             }
-            results.min_offset()
+            selection.offset
         }
     };
 
@@ -137,34 +231,52 @@ fn main() -> Result<()> {
     };
 
     if !cli.print {
-        match cli.adjtime {
-            true => {
-                let timeval_delta = delta.timeval();
-
-                let ret = unsafe { libc::adjtime(&timeval_delta, std::ptr::null_mut()) };
-                if ret != 0 {
-                    bail!(
-                        "Failed to set time with adjtime: {}",
-                        std::io::Error::last_os_error()
-                    );
-                }
+        set_clock(delta, cli.adjtime)?;
+    }
+    Ok(())
+}
+
+/// Ensures exactly one host was given, for modes that don't support combining several peers.
+fn single_host(hosts: &[String]) -> Result<&str> {
+    match hosts {
+        [host] => Ok(host.as_str()),
+        _ => bail!(
+            "This mode only supports a single host, got {}",
+            hosts.len()
+        ),
+    }
+}
+
+/// Adjusts the local clock by `delta`, either gradually via `adjtime(2)` or instantly via
+/// `settimeofday(2)`.
+pub(crate) fn set_clock(delta: Duration, adjtime: bool) -> Result<()> {
+    match adjtime {
+        true => {
+            let timeval_delta = delta.timeval();
+
+            let ret = unsafe { libc::adjtime(&timeval_delta, std::ptr::null_mut()) };
+            if ret != 0 {
+                bail!(
+                    "Failed to set time with adjtime: {}",
+                    std::io::Error::last_os_error()
+                );
             }
-            false => {
-                let new_time = chrono::Utc::now() + delta;
-                let new_tv = libc::timeval {
-                    tv_sec: new_time.timestamp(),
-                    tv_usec: new_time.timestamp_subsec_micros() as libc::suseconds_t,
-                };
-
-                let ret = unsafe { libc::settimeofday(&new_tv, std::ptr::null()) };
-                if ret != 0 {
-                    bail!(
-                        "Failed to set time with settimeofday: {}",
-                        std::io::Error::last_os_error()
-                    );
-                }
+        }
+        false => {
+            let new_time = chrono::Utc::now() + delta;
+            let new_tv = libc::timeval {
+                tv_sec: new_time.timestamp(),
+                tv_usec: new_time.timestamp_subsec_micros() as libc::suseconds_t,
+            };
+
+            let ret = unsafe { libc::settimeofday(&new_tv, std::ptr::null()) };
+            if ret != 0 {
+                bail!(
+                    "Failed to set time with settimeofday: {}",
+                    std::io::Error::last_os_error()
+                );
             }
-        };
-    }
+        }
+    };
     Ok(())
 }
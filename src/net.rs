@@ -0,0 +1,57 @@
+use anyhow::{bail, Context, Result};
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+
+/// Which IP address family to restrict a lookup to, driven by the `-4`/`-6` flags.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AddressFamily {
+    Any,
+    V4,
+    V6,
+}
+
+/// Resolves `host:port`, filtered down to `family` if it isn't `Any`.
+pub fn resolve(host: &str, port: u16, family: AddressFamily) -> Result<Vec<SocketAddr>> {
+    let addrs: Vec<SocketAddr> = (host, port)
+        .to_socket_addrs()
+        .with_context(|| format!("Failed to resolve time server {}", host))?
+        .filter(|addr| match family {
+            AddressFamily::Any => true,
+            AddressFamily::V4 => addr.is_ipv4(),
+            AddressFamily::V6 => addr.is_ipv6(),
+        })
+        .collect();
+
+    if addrs.is_empty() {
+        bail!("No addresses found for time server {}", host);
+    }
+    Ok(addrs)
+}
+
+/// Binds a UDP socket to the wildcard address matching `addr`'s family, then connects it.
+/// Binding the matching family lets us reach an IPv6-only server even though the old
+/// hardcoded `0.0.0.0:0` bind could only ever reach IPv4 ones.
+pub fn bind_and_connect_udp(addr: SocketAddr) -> Result<UdpSocket> {
+    let bind_addr = if addr.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" };
+    let socket = UdpSocket::bind(bind_addr)
+        .with_context(|| format!("Failed to bind UDP socket for {}", addr))?;
+    socket
+        .connect(addr)
+        .with_context(|| format!("Failed to connect to time server {}", addr))?;
+    Ok(socket)
+}
+
+/// Tries `f` against each address in turn, returning the first success. If all addresses
+/// fail, returns the last error encountered.
+pub fn first_success<T>(
+    addrs: &[SocketAddr],
+    mut f: impl FnMut(SocketAddr) -> Result<T>,
+) -> Result<T> {
+    let mut last_err = None;
+    for &addr in addrs {
+        match f(addr) {
+            Ok(value) => return Ok(value),
+            Err(err) => last_err = Some(err),
+        }
+    }
+    Err(last_err.expect("addrs was non-empty"))
+}
@@ -1,18 +1,65 @@
+use crate::net::{self, AddressFamily};
 use anyhow::{bail, Context, Result};
 use byteorder::{BigEndian, ReadBytesExt};
 use chrono::{DateTime, Duration, TimeZone, Utc};
 use lazy_static::lazy_static;
 use rand::random;
-use std::net::UdpSocket;
+use std::net::{Ipv4Addr, SocketAddr, UdpSocket};
+use std::os::unix::io::{AsRawFd, FromRawFd};
 
 pub const NTP_PORT: u16 = 123;
 
+/// A Kiss-o'-Death code as carried in a stratum-0 response's `reference_id`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KissCode {
+    /// `RATE`: we are polling too fast; back off.
+    RateLimit,
+    /// `DENY`/`RSTR`: the server has permanently refused to serve us.
+    Denied,
+    /// Any other 4-character kiss code.
+    Other,
+}
+
+impl KissCode {
+    fn from_ascii(code: &str) -> Self {
+        match code {
+            "RATE" => KissCode::RateLimit,
+            "DENY" | "RSTR" => KissCode::Denied,
+            _ => KissCode::Other,
+        }
+    }
+}
+
+/// Errors specific to the NTP protocol that callers may want to react to, as opposed to
+/// generic I/O or parse failures.
+#[derive(Debug)]
+pub enum NtpError {
+    /// The server sent a stratum-0 Kiss-o'-Death response instead of a time reply.
+    KissOfDeath { code: KissCode, reference_id: String },
+}
+
+impl std::fmt::Display for NtpError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            NtpError::KissOfDeath { reference_id, .. } => {
+                write!(f, "Kiss-o'-Death response (code \"{}\")", reference_id)
+            }
+        }
+    }
+}
+
+impl std::error::Error for NtpError {}
+
 #[derive(Debug)]
-struct NTPTimestamps {
+pub(crate) struct NTPTimestamps {
     t1: DateTime<Utc>, // Origin
     t2: DateTime<Utc>, // Receive
     t3: DateTime<Utc>, // Transmit
     t4: DateTime<Utc>, // Destination
+    stratum: u8,
+    reference_id: String,
+    root_delay: Duration,
+    root_dispersion: Duration,
 }
 
 impl NTPTimestamps {
@@ -30,6 +77,17 @@ impl NTPTimestamps {
     pub fn offset(&self) -> Duration {
         ((self.t2 - self.t1) + (self.t3 - self.t4)) / 2
     }
+
+    pub(crate) fn sample(&self) -> NTPSample {
+        NTPSample {
+            offset: self.offset(),
+            delay: self.delay(),
+            stratum: self.stratum,
+            reference_id: self.reference_id.clone(),
+            root_delay: self.root_delay,
+            root_dispersion: self.root_dispersion,
+        }
+    }
 }
 
 #[derive(Default, Debug, Copy, Clone)]
@@ -98,6 +156,26 @@ impl NTPPacket {
         }
     }
 
+    /// Build a server-mode reply to a client request, timestamped with the current time.
+    fn server_response(request: &NTPPacket) -> Self {
+        let now = Utc::now();
+        NTPPacket {
+            leap: 0,
+            version: request.version,
+            mode: Mode::Server as u8,
+            stratum: 1,
+            poll: request.poll,
+            precision: request.precision,
+            root_delay: 0,
+            root_dispersion: 0,
+            reference_id: u32::from_be_bytes(*b"LOCL"),
+            reference_timestamp: now.into(),
+            origin_timestamp: request.transmit_timestamp,
+            receive_timestamp: now.into(),
+            transmit_timestamp: Utc::now().into(),
+        }
+    }
+
     fn build(&self) -> Vec<u8> {
         let mut data = Vec::with_capacity(Self::MESSAGE_LENGTH);
         let li_vn_mode: u8 = self.leap << 6 | self.version << 3 | self.mode;
@@ -124,16 +202,19 @@ impl NTPPacket {
     }
 }
 
-fn ntp_roundtrip(host: &str, port: u16) -> Result<NTPTimestamps> {
+pub(crate) fn ntp_roundtrip(
+    host: &str,
+    port: u16,
+    family: AddressFamily,
+) -> Result<NTPTimestamps> {
     let timeout = std::time::Duration::new(1, 0);
 
     let mut response = [0_u8; NTPPacket::MESSAGE_LENGTH];
 
-    let udp = UdpSocket::bind("0.0.0.0:0")?;
+    let addrs = net::resolve(host, port, family)?;
+    let udp = net::first_success(&addrs, net::bind_and_connect_udp)?;
     udp.set_read_timeout(Some(timeout))?;
 
-    udp.connect((host, port))
-        .with_context(|| format!("Failed to connect to time server {}.", host))?;
     let mut client = NTPPacket::client();
 
     // We set a random transmit timestamp and compare it later with the response
@@ -162,7 +243,12 @@ fn ntp_roundtrip(host: &str, port: u16) -> Result<NTPTimestamps> {
         );
     }
     if ntp_response.stratum == 0 {
-        bail!("Bad NTP response (stratum is zero)");
+        let code = decode_reference_ascii(ntp_response.reference_id);
+        return Err(NtpError::KissOfDeath {
+            code: KissCode::from_ascii(&code),
+            reference_id: code,
+        }
+        .into());
     }
     if ntp_response.transmit_timestamp.seconds == 0 || ntp_response.transmit_timestamp.fraction == 0
     {
@@ -172,10 +258,54 @@ fn ntp_roundtrip(host: &str, port: u16) -> Result<NTPTimestamps> {
         bail!("Bad NTP response (response's origin_timestamp does not equal request's transmit_timestamp)");
     }
 
+    let root_delay = short_to_duration(ntp_response.root_delay);
+    let root_dispersion = short_to_duration(ntp_response.root_dispersion);
+    let max_root_dispersion = Duration::seconds(16);
+    if root_dispersion > max_root_dispersion {
+        bail!(
+            "Bad NTP response (root dispersion {}ms implausibly large)",
+            root_dispersion.num_milliseconds()
+        );
+    }
+
     let t2: DateTime<Utc> = ntp_response.receive_timestamp.into();
     let t3: DateTime<Utc> = ntp_response.transmit_timestamp.into();
 
-    Ok(NTPTimestamps { t1, t2, t3, t4 })
+    Ok(NTPTimestamps {
+        t1,
+        t2,
+        t3,
+        t4,
+        stratum: ntp_response.stratum,
+        reference_id: decode_reference_id(ntp_response.stratum, ntp_response.reference_id),
+        root_delay,
+        root_dispersion,
+    })
+}
+
+/// Decodes a 4-byte `reference_id` as an ASCII kiss code / refclock name, trimming NUL padding.
+fn decode_reference_ascii(reference_id: u32) -> String {
+    String::from_utf8_lossy(&reference_id.to_be_bytes())
+        .trim_end_matches('\0')
+        .to_string()
+}
+
+/// Decodes a response's `reference_id` per its stratum: an IPv4 address for stratum >= 2, or
+/// a 4-character reference clock name for stratum 1.
+fn decode_reference_id(stratum: u8, reference_id: u32) -> String {
+    if stratum >= 2 {
+        Ipv4Addr::from(reference_id).to_string()
+    } else {
+        decode_reference_ascii(reference_id)
+    }
+}
+
+/// Converts an NTP short (16.16 fixed-point seconds) format value, as used for `root_delay`
+/// and `root_dispersion`, to a `Duration`.
+fn short_to_duration(value: u32) -> Duration {
+    let seconds = (value >> 16) as i64;
+    let fraction = (value & 0xFFFF) as f64 / 65536.0;
+    Duration::nanoseconds(seconds * 1_000_000_000 + (fraction * 1e9).round() as i64)
 }
 
 #[derive(Eq, PartialEq, Default, Debug, Copy, Clone)]
@@ -195,22 +325,54 @@ impl From<NTPTimestamp> for DateTime<Utc> {
     }
 }
 
+impl From<DateTime<Utc>> for NTPTimestamp {
+    fn from(time: DateTime<Utc>) -> Self {
+        let nano_to_fraction = 2_f64.powi(32) / 1e9;
+        NTPTimestamp {
+            seconds: (time.timestamp() + NTP_EPOCH) as u32,
+            fraction: (time.timestamp_subsec_nanos() as f64 * nano_to_fraction).round() as u32,
+        }
+    }
+}
+
+/// A single successful NTP round trip, with the server quality metadata needed to judge it.
+#[derive(Debug, Clone)]
+pub struct NTPSample {
+    pub offset: Duration,
+    pub delay: Duration,
+    pub stratum: u8,
+    pub reference_id: String,
+    pub root_delay: Duration,
+    pub root_dispersion: Duration,
+}
+
 pub struct NTPResults {
-    results: Vec<(Duration, Duration)>, // (offset, delay)
+    results: Vec<NTPSample>,
 }
 
 impl NTPResults {
+    /// Build an `NTPResults` from a caller-maintained window of samples, e.g. the rolling
+    /// window kept by the disciplining daemon.
+    pub(crate) fn from_samples(results: Vec<NTPSample>) -> Self {
+        NTPResults { results }
+    }
+
+    /// The sample with the lowest delay, i.e. the one `min_offset`/`min_delay` are based on.
+    pub fn best(&self) -> Option<&NTPSample> {
+        self.results.iter().min_by_key(|s| s.delay)
+    }
+
     /// Return the jitter (psi) of the results in nanoseconds
     pub fn jitter(&self) -> f64 {
-        let min_offset_by_delay = match self.results.iter().min_by_key(|k| k.1) {
-            Some(min) => min.0,
+        let min_offset_by_delay = match self.best() {
+            Some(best) => best.offset,
             None => Duration::seconds(0), // This will only happen when self.results is empty, and in this case the following iteration will be trivial anyway
         };
         let psi = self
             .results
             .iter()
-            .map(|&x| {
-                ((x.0 - min_offset_by_delay)
+            .map(|x| {
+                ((x.offset - min_offset_by_delay)
                     .num_nanoseconds()
                     .expect("This should never overflow") as f64)
                     .powi(2)
@@ -223,36 +385,53 @@ impl NTPResults {
     }
 
     pub fn min_offset(&self) -> Duration {
-        match self.results.iter().min_by_key(|k| k.1) {
-            Some(min) => min.0,
+        match self.best() {
+            Some(best) => best.offset,
             None => Duration::seconds(0), // This will only happen when self.results is empty
         }
     }
 
     pub fn min_delay(&self) -> Duration {
-        match self.results.iter().min_by_key(|k| k.1) {
-            Some(min) => min.1,
+        match self.best() {
+            Some(best) => best.delay,
             None => Duration::seconds(0), // This will only happen when self.results is empty
         }
     }
 }
 /// Performs an SNTP (RFC 5905) query.
-pub fn ntp_query(host: &str, port: u16) -> Result<NTPResults> {
+pub fn ntp_query(host: &str, port: u16, family: AddressFamily) -> Result<NTPResults> {
     const NUM_TIMINGS: usize = 8;
     let mut results = NTPResults {
         results: Vec::with_capacity(8),
     };
     for i in 1..25 {
-        let ntp_result = match ntp_roundtrip(host, port) {
+        let ntp_result = match ntp_roundtrip(host, port, family) {
             Ok(result) => result,
             Err(err) => {
+                if let Some(NtpError::KissOfDeath { code, .. }) = err.downcast_ref::<NtpError>() {
+                    match code {
+                        KissCode::RateLimit => {
+                            eprintln!(
+                                "NTP server {} asked us to slow down (Kiss-o'-Death RATE); stopping this burst",
+                                host
+                            );
+                            break;
+                        }
+                        KissCode::Denied => {
+                            bail!(
+                                "NTP server {} permanently refused service (Kiss-o'-Death {})",
+                                host,
+                                err
+                            );
+                        }
+                        KissCode::Other => {}
+                    }
+                }
                 eprintln!("NTP query failed (attempt {}): {}", i, err);
                 continue;
             }
         };
-        results
-            .results
-            .push((ntp_result.offset(), ntp_result.delay()));
+        results.results.push(ntp_result.sample());
 
         if results.results.len() >= NUM_TIMINGS {
             break;
@@ -267,6 +446,95 @@ pub fn ntp_query(host: &str, port: u16) -> Result<NTPResults> {
     }
 }
 
+/// The outcome of combining several peers' results via [`select_peers`].
+pub struct Selection {
+    /// The weighted-average offset of the accepted peers ("truechimers").
+    pub offset: Duration,
+    /// Hosts whose offset interval overlapped the selected cluster.
+    pub accepted: Vec<String>,
+    /// Hosts discarded as outliers ("falsetickers").
+    pub rejected: Vec<String>,
+}
+
+/// Combines several peers' results into a single offset, the way NTP's own selection
+/// algorithm does: each peer contributes an offset interval `[offset - (delay/2 + jitter),
+/// offset + (delay/2 + jitter)]`, we find the point covered by the largest number of
+/// mutually-overlapping intervals (the truechimers), discard the rest as falsetickers, and
+/// average the survivors' offsets weighted by inverse delay.
+pub fn select_peers(peers: Vec<(String, NTPResults)>) -> Result<Selection> {
+    struct Candidate {
+        host: String,
+        offset: f64, // seconds
+        weight: f64, // 1 / delay
+        lo: f64,
+        hi: f64,
+    }
+
+    let candidates: Vec<Candidate> = peers
+        .iter()
+        .filter_map(|(host, results)| {
+            let best = results.best()?;
+            let offset = best.offset.num_microseconds()? as f64 / 1e6;
+            let delay = (best.delay.num_microseconds()? as f64 / 1e6).max(1e-6);
+            let radius = delay / 2.0 + results.jitter();
+            Some(Candidate {
+                host: host.clone(),
+                offset,
+                weight: 1.0 / delay,
+                lo: offset - radius,
+                hi: offset + radius,
+            })
+        })
+        .collect();
+
+    if candidates.is_empty() {
+        bail!("No usable NTP peers to select from");
+    }
+
+    // Sweep line over interval endpoints to find the point covered by the most intervals.
+    let mut events: Vec<(f64, i32)> = Vec::with_capacity(candidates.len() * 2);
+    for c in &candidates {
+        events.push((c.lo, 1));
+        events.push((c.hi, -1));
+    }
+    events.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap().then(b.1.cmp(&a.1)));
+
+    let mut count = 0;
+    let mut best_count = 0;
+    let mut best_point = candidates[0].offset;
+    for (point, delta) in events {
+        count += delta;
+        if count > best_count {
+            best_count = count;
+            best_point = point;
+        }
+    }
+
+    let mut accepted = Vec::new();
+    let mut rejected = Vec::new();
+    let mut weighted_sum = 0.0;
+    let mut weight_total = 0.0;
+    for c in &candidates {
+        if c.lo <= best_point && best_point <= c.hi {
+            weighted_sum += c.offset * c.weight;
+            weight_total += c.weight;
+            accepted.push(c.host.clone());
+        } else {
+            rejected.push(c.host.clone());
+        }
+    }
+
+    if weight_total == 0.0 {
+        bail!("Selection algorithm failed to find any truechimers");
+    }
+
+    Ok(Selection {
+        offset: Duration::microseconds(((weighted_sum / weight_total) * 1e6).round() as i64),
+        accepted,
+        rejected,
+    })
+}
+
 /// Returns the precision of the system clock.
 ///
 /// This is system rho from the NTP RFC.
@@ -285,3 +553,166 @@ lazy_static! {
 /// The system's clock precision
 pub static ref RHO: f64 = clock_precision();
 }
+
+/// Bind a UDP socket to `addr` with `SO_REUSEPORT` set, so several worker threads can
+/// share the same bind address and let the kernel load-balance incoming datagrams.
+/// `addr` may be either an IPv4 or an IPv6 address; the socket family follows it.
+fn bind_reuseport(addr: SocketAddr) -> Result<UdpSocket> {
+    let domain = if addr.is_ipv4() {
+        libc::AF_INET
+    } else {
+        libc::AF_INET6
+    };
+    let fd = unsafe { libc::socket(domain, libc::SOCK_DGRAM, 0) };
+    if fd < 0 {
+        bail!(
+            "Failed to create NTP server socket: {}",
+            std::io::Error::last_os_error()
+        );
+    }
+    // Safety: fd was just created above and is owned exclusively by this UdpSocket from here on.
+    let socket = unsafe { UdpSocket::from_raw_fd(fd) };
+
+    let reuseport: libc::c_int = 1;
+    let ret = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_REUSEPORT,
+            &reuseport as *const _ as *const libc::c_void,
+            std::mem::size_of_val(&reuseport) as libc::socklen_t,
+        )
+    };
+    if ret != 0 {
+        bail!(
+            "Failed to set SO_REUSEPORT on NTP server socket: {}",
+            std::io::Error::last_os_error()
+        );
+    }
+
+    let ret = match addr {
+        SocketAddr::V4(addr) => {
+            let sockaddr = libc::sockaddr_in {
+                sin_family: libc::AF_INET as libc::sa_family_t,
+                sin_port: addr.port().to_be(),
+                sin_addr: libc::in_addr {
+                    s_addr: u32::from_ne_bytes(addr.ip().octets()),
+                },
+                sin_zero: [0; 8],
+            };
+            unsafe {
+                libc::bind(
+                    socket.as_raw_fd(),
+                    &sockaddr as *const _ as *const libc::sockaddr,
+                    std::mem::size_of::<libc::sockaddr_in>() as libc::socklen_t,
+                )
+            }
+        }
+        SocketAddr::V6(addr) => {
+            let sockaddr = libc::sockaddr_in6 {
+                sin6_family: libc::AF_INET6 as libc::sa_family_t,
+                sin6_port: addr.port().to_be(),
+                sin6_flowinfo: addr.flowinfo(),
+                sin6_addr: libc::in6_addr {
+                    s6_addr: addr.ip().octets(),
+                },
+                sin6_scope_id: addr.scope_id(),
+            };
+            unsafe {
+                libc::bind(
+                    socket.as_raw_fd(),
+                    &sockaddr as *const _ as *const libc::sockaddr,
+                    std::mem::size_of::<libc::sockaddr_in6>() as libc::socklen_t,
+                )
+            }
+        }
+    };
+    if ret != 0 {
+        bail!(
+            "Failed to bind NTP server socket to {}: {}",
+            addr,
+            std::io::Error::last_os_error()
+        );
+    }
+
+    Ok(socket)
+}
+
+/// Serve NTP client requests on `bind_addr:port`, spawning `threads` worker threads that
+/// each bind their own `SO_REUSEPORT` socket to the same address. `bind_addr` is resolved
+/// like any other host, filtered to `family` when `-4`/`-6` was given, so this serves over
+/// IPv6 just as readily as IPv4.
+pub fn serve(bind_addr: &str, port: u16, threads: usize, family: AddressFamily) -> Result<()> {
+    let socket_addr = net::resolve(bind_addr, port, family)?[0];
+
+    let handles: Vec<_> = (0..threads.max(1))
+        .map(|_| std::thread::spawn(move || -> Result<()> { serve_worker(socket_addr) }))
+        .collect();
+
+    for handle in handles {
+        handle.join().expect("NTP server worker thread panicked")?;
+    }
+    Ok(())
+}
+
+fn serve_worker(addr: SocketAddr) -> Result<()> {
+    let socket = bind_reuseport(addr)?;
+    let mut buf = [0_u8; NTPPacket::MESSAGE_LENGTH];
+    loop {
+        let (len, src) = match socket.recv_from(&mut buf) {
+            Ok(result) => result,
+            Err(err) => {
+                eprintln!("Failed to receive NTP request: {}", err);
+                continue;
+            }
+        };
+        let request = match NTPPacket::parse(&buf[..len]) {
+            Ok(packet) => packet,
+            Err(err) => {
+                eprintln!("Bad NTP request from {}: {}", src, err);
+                continue;
+            }
+        };
+        let response = NTPPacket::server_response(&request).build();
+        if let Err(err) = socket.send_to(&response, src) {
+            eprintln!("Failed to send NTP response to {}: {}", src, err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Two identical samples so `jitter()` is well-defined (zero) without touching the
+    /// disagreement logic this test isn't exercising.
+    fn peer(host: &str, offset_ms: i64, delay_ms: i64) -> (String, NTPResults) {
+        let sample = NTPSample {
+            offset: Duration::milliseconds(offset_ms),
+            delay: Duration::milliseconds(delay_ms),
+            stratum: 1,
+            reference_id: "TEST".to_string(),
+            root_delay: Duration::zero(),
+            root_dispersion: Duration::zero(),
+        };
+        (
+            host.to_string(),
+            NTPResults::from_samples(vec![sample.clone(), sample]),
+        )
+    }
+
+    #[test]
+    fn select_peers_rejects_clear_outlier() {
+        let selection = select_peers(vec![
+            peer("a", 10, 20),
+            peer("b", 12, 20),
+            peer("c", 500, 20),
+        ])
+        .expect("selection should succeed");
+
+        assert_eq!(selection.accepted, vec!["a".to_string(), "b".to_string()]);
+        assert_eq!(selection.rejected, vec!["c".to_string()]);
+        let offset_ms = selection.offset.num_milliseconds();
+        assert!((10..=12).contains(&offset_ms), "offset was {}ms", offset_ms);
+    }
+}
@@ -1,15 +1,19 @@
+use crate::net::{self, AddressFamily};
 use anyhow::{ensure, Context, Result};
 use chrono::{Duration, Utc};
 use std::io::prelude::*;
-use std::net::{TcpStream, UdpSocket};
+use std::net::{TcpListener, TcpStream, UdpSocket};
 
 const TIME_EPOCH: i64 = 2_208_988_800;
 const DEFAULT_TIMEOUT: std::time::Duration = std::time::Duration::new(1, 500);
 
 /// Gets the time offset from a RFC868 server over TCP
-pub fn get_time_tcp(host: &str, port: u16) -> Result<Duration> {
-    let mut socket = TcpStream::connect((host, port))
-        .with_context(|| format!("Failed to connect to time server {}.", host))?;
+pub fn get_time_tcp(host: &str, port: u16, family: AddressFamily) -> Result<Duration> {
+    let addrs = net::resolve(host, port, family)?;
+    let mut socket = net::first_success(&addrs, |addr| {
+        TcpStream::connect(addr)
+            .with_context(|| format!("Failed to connect to time server {}.", addr))
+    })?;
     let mut buf = [0; 4];
     socket.set_read_timeout(Some(DEFAULT_TIMEOUT))?;
     let received = socket
@@ -26,11 +30,9 @@ pub fn get_time_tcp(host: &str, port: u16) -> Result<Duration> {
 }
 
 /// Gets the time offset from a RFC868 server over UDP
-pub fn get_time_udp(host: &str, port: u16) -> Result<Duration> {
-    let socket = UdpSocket::bind("0.0.0.0:0")?;
-    socket
-        .connect((host, port))
-        .with_context(|| format!("Failed to connect to time server {}.", host))?;
+pub fn get_time_udp(host: &str, port: u16, family: AddressFamily) -> Result<Duration> {
+    let addrs = net::resolve(host, port, family)?;
+    let socket = net::first_success(&addrs, net::bind_and_connect_udp)?;
     socket.set_read_timeout(Some(DEFAULT_TIMEOUT))?;
     socket
         .send("".as_bytes())
@@ -52,3 +54,55 @@ pub fn get_time_udp(host: &str, port: u16) -> Result<Duration> {
         server_time * 1000 - Utc::now().timestamp_millis(),
     ))
 }
+
+/// The current time as a 4-byte big-endian RFC 868 timestamp.
+fn current_time_be_bytes() -> [u8; 4] {
+    let seconds = (Utc::now().timestamp() + TIME_EPOCH) as u32;
+    seconds.to_be_bytes()
+}
+
+/// Serves RFC 868 time to clients on `bind_addr:port`, over both TCP and UDP.
+pub fn serve(bind_addr: &str, port: u16, family: AddressFamily) -> Result<()> {
+    let socket_addr = net::resolve(bind_addr, port, family)?[0];
+
+    let udp_socket = UdpSocket::bind(socket_addr)
+        .with_context(|| format!("Failed to bind RFC 868 UDP socket on {}", socket_addr))?;
+    let udp_thread = std::thread::spawn(move || serve_udp(udp_socket));
+
+    let tcp_listener = TcpListener::bind(socket_addr)
+        .with_context(|| format!("Failed to bind RFC 868 TCP listener on {}", socket_addr))?;
+    for stream in tcp_listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                if let Err(err) = respond_tcp(stream) {
+                    eprintln!("RFC 868 TCP client error: {}", err);
+                }
+            }
+            Err(err) => eprintln!("RFC 868 TCP accept error: {}", err),
+        }
+    }
+
+    udp_thread.join().expect("RFC 868 UDP server thread panicked")
+}
+
+fn respond_tcp(mut stream: TcpStream) -> Result<()> {
+    stream
+        .write_all(&current_time_be_bytes())
+        .context("Failed to send RFC 868 response")
+}
+
+fn serve_udp(socket: UdpSocket) -> Result<()> {
+    let mut buf = [0_u8; 64];
+    loop {
+        let (_, src) = match socket.recv_from(&mut buf) {
+            Ok(result) => result,
+            Err(err) => {
+                eprintln!("Failed to receive RFC 868 request: {}", err);
+                continue;
+            }
+        };
+        if let Err(err) = socket.send_to(&current_time_be_bytes(), src) {
+            eprintln!("Failed to send RFC 868 response to {}: {}", src, err);
+        }
+    }
+}
@@ -0,0 +1,154 @@
+use crate::net::AddressFamily;
+use crate::ntp::{self, KissCode, NtpError};
+use anyhow::{bail, Result};
+use chrono::{DateTime, Duration, Utc};
+use std::collections::VecDeque;
+use std::thread;
+use std::time::Duration as StdDuration;
+
+/// Number of (local_time, offset) samples kept for the frequency fit.
+const WINDOW: usize = 12;
+const POLL_INTERVAL_SECS: f64 = 16.0;
+const MAX_POLL_INTERVAL_SECS: f64 = 60.0;
+
+struct Sample {
+    local_time: DateTime<Utc>,
+    offset: Duration,
+}
+
+/// Runs a continuous clock-discipline loop that estimates the local oscillator's frequency
+/// error (skew) from a sliding window of NTP offset samples and corrects both the phase and
+/// the frequency of the system clock via `adjtimex`, so the clock stays disciplined between
+/// polls instead of drifting back.
+pub fn run(host: &str, port: u16, verbose: u8, family: AddressFamily, print: bool) -> Result<()> {
+    let mut samples: VecDeque<Sample> = VecDeque::with_capacity(WINDOW);
+    let mut poll_interval = POLL_INTERVAL_SECS;
+
+    loop {
+        match ntp::ntp_roundtrip(host, port, family) {
+            Ok(timestamps) => {
+                poll_interval = POLL_INTERVAL_SECS;
+                let offset = timestamps.offset();
+                if samples.len() == WINDOW {
+                    samples.pop_front();
+                }
+                samples.push_back(Sample {
+                    local_time: Utc::now(),
+                    offset,
+                });
+
+                if samples.len() >= 2 {
+                    let (ppm, residual) = fit_skew(&samples);
+                    if verbose > 0 {
+                        println!(
+                            "Estimated drift: {:.3}ppm, residual offset {:.6}s",
+                            ppm,
+                            residual.num_microseconds().unwrap_or(0) as f64 / 1e6
+                        );
+                    }
+                    apply_skew(ppm, residual, print)?;
+                } else if !print {
+                    // Not enough samples yet to fit a slope; slew the raw offset in the
+                    // meantime so we don't wait a full window before correcting anything.
+                    crate::set_clock(offset, true)?;
+                }
+            }
+            Err(err) => match err.downcast_ref::<NtpError>() {
+                Some(NtpError::KissOfDeath { code, .. }) => match code {
+                    KissCode::RateLimit => {
+                        eprintln!(
+                            "{}: server asked us to slow down (Kiss-o'-Death RATE), backing off",
+                            host
+                        );
+                        poll_interval = (poll_interval * 2.0).min(MAX_POLL_INTERVAL_SECS);
+                    }
+                    KissCode::Denied => {
+                        bail!(
+                            "{}: server permanently refused service (Kiss-o'-Death); stopping",
+                            host
+                        );
+                    }
+                    KissCode::Other => {
+                        eprintln!("NTP query to {} failed: {}", host, err);
+                    }
+                },
+                None => eprintln!("NTP query to {} failed: {}", host, err),
+            },
+        }
+
+        thread::sleep(StdDuration::from_secs_f64(poll_interval));
+    }
+}
+
+/// Least-squares fit of offset against local elapsed time, returning the slope as a
+/// parts-per-million frequency error and the intercept as a residual phase offset.
+fn fit_skew(samples: &VecDeque<Sample>) -> (f64, Duration) {
+    let t0 = samples.front().expect("at least one sample").local_time;
+    let xs: Vec<f64> = samples
+        .iter()
+        .map(|s| (s.local_time - t0).num_microseconds().unwrap_or(0) as f64 / 1e6)
+        .collect();
+    let ys: Vec<f64> = samples
+        .iter()
+        .map(|s| s.offset.num_microseconds().unwrap_or(0) as f64 / 1e6)
+        .collect();
+
+    let n = xs.len() as f64;
+    let mean_x = xs.iter().sum::<f64>() / n;
+    let mean_y = ys.iter().sum::<f64>() / n;
+
+    let mut covariance = 0.0;
+    let mut variance = 0.0;
+    for (x, y) in xs.iter().zip(ys.iter()) {
+        covariance += (x - mean_x) * (y - mean_y);
+        variance += (x - mean_x).powi(2);
+    }
+    let slope = if variance > 0.0 { covariance / variance } else { 0.0 };
+    let intercept = mean_y - slope * mean_x;
+
+    (slope * 1e6, Duration::microseconds((intercept * 1e6) as i64))
+}
+
+/// Applies the estimated frequency error (in ppm) and residual phase offset via `adjtimex`.
+/// With `print` set, the correction is computed but never applied, for dry runs.
+fn apply_skew(ppm: f64, offset: Duration, print: bool) -> Result<()> {
+    if print {
+        return Ok(());
+    }
+    let mut tx: libc::timex = unsafe { std::mem::zeroed() };
+    tx.modes = (libc::ADJ_FREQUENCY | libc::ADJ_OFFSET | libc::ADJ_STATUS) as libc::c_uint;
+    tx.status = libc::STA_PLL;
+    tx.freq = (ppm * 65536.0) as libc::c_long;
+    tx.offset = offset.num_microseconds().unwrap_or(0) as libc::c_long;
+
+    let ret = unsafe { libc::adjtimex(&mut tx) };
+    if ret < 0 {
+        bail!(
+            "Failed to discipline clock with adjtimex: {}",
+            std::io::Error::last_os_error()
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fit_skew_recovers_linear_drift() {
+        // Offset grows by 10ms every 10s, i.e. a 1000ppm frequency error.
+        let t0 = Utc::now();
+        let mut samples = VecDeque::new();
+        for i in 0..6 {
+            samples.push_back(Sample {
+                local_time: t0 + Duration::seconds(i * 10),
+                offset: Duration::milliseconds(i * 10),
+            });
+        }
+
+        let (ppm, residual) = fit_skew(&samples);
+        assert!((ppm - 1000.0).abs() < 1.0, "expected ~1000ppm, got {}", ppm);
+        assert!(residual.num_microseconds().unwrap_or(i64::MAX).abs() < 1_000);
+    }
+}